@@ -0,0 +1,83 @@
+//! Turning the paths given on the command line (files, directories, or a mix
+//! of both) into a deterministic, sorted list of markdown files to process.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// Expand `paths` into the markdown files they name, recursing into any
+/// directories to find `*.md`/`*.markdown` files. The result is sorted and
+/// deduplicated so repeated runs visit files in the same order.
+pub fn discover_files(paths: &[&str]) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    for path in paths {
+        visit(Path::new(path), &mut found)?;
+    }
+    found.sort();
+    found.dedup();
+    Ok(found)
+}
+
+fn visit(path: &Path, found: &mut Vec<PathBuf>) -> Result<()> {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            visit(&entry?.path(), found)?;
+        }
+    } else if is_markdown(path) {
+        found.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+fn is_markdown(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("md") | Some("markdown")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn discover_files_recurses_directories_and_sorts() {
+        let dir = std::env::temp_dir().join(format!(
+            "mdbabel-discover-test-{:?}",
+            std::thread::current().id()
+        ));
+        let nested = dir.join("nested");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(dir.join("b.md"), "").unwrap();
+        fs::write(dir.join("a.markdown"), "").unwrap();
+        fs::write(dir.join("ignore.txt"), "").unwrap();
+        fs::write(nested.join("c.md"), "").unwrap();
+
+        let found = discover_files(&[dir.to_str().unwrap()]).unwrap();
+
+        assert_eq!(
+            found,
+            vec![
+                dir.join("a.markdown"),
+                dir.join("b.md"),
+                nested.join("c.md"),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn discover_files_passes_through_explicit_file() {
+        let file = std::env::temp_dir().join(format!(
+            "mdbabel-discover-test-file-{:?}.md",
+            std::thread::current().id()
+        ));
+        fs::write(&file, "").unwrap();
+
+        let found = discover_files(&[file.to_str().unwrap()]).unwrap();
+        assert_eq!(found, vec![file.clone()]);
+
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -1,58 +1,487 @@
-use crate::markdown::CodeBlockBody;
+use crate::markdown::{CodeBlockBody, CodeBlockHeader};
 use anyhow::{anyhow, Result};
 use std::collections::HashMap;
-use std::process::{Command, Output, Stdio};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How a `LangExecutor` feeds a block's code to its interpreter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecutionStrategy {
+    /// Pass the code as the final argv entry, e.g. `sh -c '<code>'`. Only
+    /// works for interpreters that accept a script as an argument.
+    Arg,
+    /// Write the code to the child's stdin and close it to signal EOF.
+    /// Required for interpreters like Python, Ruby, Node, and Perl that
+    /// read a multi-line program from stdin.
+    Stdin,
+}
+
+/// How to make a language's interpreter echo a sentinel line, used to find
+/// the end of a block's output when running inside a shared `:session`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SentinelStyle {
+    Shell,
+    Python,
+    Ruby,
+    Node,
+    Perl,
+}
+
+/// None of Ruby, Node, or Perl ship a REPL that cleanly executes arbitrary
+/// code piped in line by line without talking back (a REPL's own `-i`-style
+/// flag echoes every expression's value, which would contaminate captured
+/// output), so a `:session ruby`/`:session node`/`:session perl` block is
+/// driven through one of these tiny `-e` programs instead of the bare
+/// interpreter: each reads one line at a time and evaluates it, rather than
+/// compiling the whole program up front the way the one-shot executors do.
+/// As a consequence, a session block in these languages must be complete on
+/// every line (e.g. no statement split across lines).
+const RUBY_SESSION_DRIVER: &str =
+    "STDOUT.sync = true; STDIN.each_line { |l| begin; eval(l); rescue => e; warn e; end }";
+const NODE_SESSION_DRIVER: &str = "const rl = require('readline').createInterface({ input: process.stdin, terminal: false }); rl.on('line', (line) => { try { eval(line); } catch (e) { console.error(e); } });";
+const PERL_SESSION_DRIVER: &str = "$|=1; while (<STDIN>) { eval $_; warn $@ if $@; }";
+
+impl SentinelStyle {
+    /// A snippet that prints `marker` on its own line, in the target
+    /// language's syntax.
+    fn echo_command(&self, marker: &str) -> String {
+        match self {
+            SentinelStyle::Shell => format!("printf '%s\\n' '{}'\n", marker),
+            SentinelStyle::Python => format!("print('{}')\n", marker),
+            SentinelStyle::Ruby => format!("puts '{}'\n", marker),
+            SentinelStyle::Node => format!("console.log('{}')\n", marker),
+            SentinelStyle::Perl => format!("print '{}', \"\\n\";\n", marker),
+        }
+    }
+}
 
 /// Execute code blocks for a particular language.
 pub struct LangExecutor {
     program: String,
     base_args: Vec<String>,
+    /// The program used to spawn a long-lived `:session` interpreter. Kept
+    /// separate from `program`, though every default executor uses the same
+    /// binary for both (Ruby, Node, and Perl sessions differ only in their
+    /// `session_args`, which run a line-by-line `-e` driver).
+    session_program: String,
+    /// Args used when spawning a `:session` interpreter. These are their
+    /// own thing, not `base_args`: `base_args` is tuned for the one-shot
+    /// `ExecutionStrategy` (e.g. `sh`'s `-c`, which needs a script argument
+    /// that a session never supplies), while a session needs whatever makes
+    /// the interpreter read and execute statements incrementally from a
+    /// pipe instead of waiting for EOF.
+    session_args: Vec<String>,
+    strategy: ExecutionStrategy,
+    sentinel: SentinelStyle,
 }
 
 impl LangExecutor {
-    pub fn new(program: String, base_args: Vec<String>) -> Self {
-        LangExecutor { program, base_args }
+    pub fn new(
+        program: String,
+        base_args: Vec<String>,
+        strategy: ExecutionStrategy,
+        sentinel: SentinelStyle,
+        session_program: String,
+        session_args: Vec<String>,
+    ) -> Self {
+        LangExecutor {
+            program,
+            base_args,
+            session_program,
+            session_args,
+            strategy,
+            sentinel,
+        }
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(self.program.clone());
+        cmd.args(self.base_args.clone());
+        cmd
     }
 
     pub fn execute_body(&self, body: &CodeBlockBody) -> Result<()> {
-        let _ = Command::new(self.program.clone())
-            .args(self.base_args.clone())
-            .arg(body.code.clone())
-            .spawn()?
-            .wait()?;
+        match self.strategy {
+            ExecutionStrategy::Arg => {
+                let _ = self.command().arg(body.code.clone()).spawn()?.wait()?;
+            }
+            ExecutionStrategy::Stdin => {
+                let mut child = self.command().stdin(Stdio::piped()).spawn()?;
+                write_stdin_and_close(&mut child, &body.code)?;
+                child.wait()?;
+            }
+        }
         Ok(())
     }
+
+    /// Run the body to completion, capturing its stdout/stderr instead of
+    /// inheriting the parent's.
+    pub fn execute_body_captured(&self, body: &CodeBlockBody) -> Result<Output> {
+        let output = match self.strategy {
+            ExecutionStrategy::Arg => self.command().arg(body.code.clone()).output()?,
+            ExecutionStrategy::Stdin => {
+                let mut child = self
+                    .command()
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+                // Write stdin on its own thread instead of up front: a block
+                // that writes enough stdout/stderr before it's done reading
+                // stdin can fill the OS pipe buffer and block, which would
+                // deadlock against us still writing the rest of its input.
+                let writer = spawn_stdin_writer(&mut child, body.code.clone())?;
+                let output = child.wait_with_output()?;
+                match writer.join() {
+                    Ok(result) => result?,
+                    Err(_) => return Err(anyhow!("stdin writer thread panicked")),
+                }
+                output
+            }
+        };
+        Ok(output)
+    }
+
+    /// Spawn a long-lived interpreter process for use as a `:session`.
+    fn spawn_session(&self) -> Result<Session> {
+        Session::spawn(&self.session_program, &self.session_args, self.sentinel)
+    }
 }
 
-pub struct Executors(HashMap<String, LangExecutor>);
+/// Write `code` to the child's stdin, then drop the handle so the child sees
+/// EOF.
+fn write_stdin_and_close(child: &mut Child, code: &str) -> Result<()> {
+    let mut stdin: ChildStdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("child stdin was not piped"))?;
+    stdin.write_all(code.as_bytes())?;
+    Ok(())
+}
+
+/// Take `child`'s stdin and write `code` to it on a background thread,
+/// closing it (by dropping the handle when the thread finishes) once done.
+/// Callers that also read the child's stdout/stderr must start that read
+/// before (or concurrently with) joining this thread, not after writing
+/// synchronously, to avoid a pipe-buffer deadlock.
+fn spawn_stdin_writer(child: &mut Child, code: String) -> Result<thread::JoinHandle<Result<()>>> {
+    let mut stdin: ChildStdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("child stdin was not piped"))?;
+    Ok(thread::spawn(move || {
+        stdin.write_all(code.as_bytes())?;
+        Ok(())
+    }))
+}
+
+/// A long-running interpreter process shared by every code block that
+/// declares the same `:session` name, so state (shell variables, a Python
+/// REPL's globals, ...) persists across blocks.
+struct Session {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    sentinel: SentinelStyle,
+}
+
+impl Session {
+    fn spawn(program: &str, base_args: &[String], sentinel: SentinelStyle) -> Result<Self> {
+        let mut child = Command::new(program)
+            .args(base_args.to_vec())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = BufReader::new(
+            child
+                .stdout
+                .take()
+                .ok_or_else(|| anyhow!("child stdout was not piped"))?,
+        );
+        Ok(Session {
+            child,
+            stdout,
+            sentinel,
+        })
+    }
+
+    /// Run `code` in this session, returning everything written to stdout
+    /// before the sentinel line marking the end of this block's output.
+    fn run(&mut self, code: &str) -> Result<String> {
+        let marker = format!("<<<MDBABEL:{}>>>", random_token());
+
+        let mut payload = code.to_owned();
+        if !code.ends_with('\n') {
+            payload.push('\n');
+        }
+        payload.push_str(&self.sentinel.echo_command(&marker));
+
+        // Write on a background thread instead of up front: a block that
+        // prints enough output before it's done reading its input (e.g. a
+        // shell echoing as it goes) can fill the stdout pipe buffer and
+        // block, which would deadlock against us still writing the rest of
+        // its input synchronously. The thread hands the stdin handle back
+        // once it's done so the session can be reused for the next block.
+        let mut stdin = self
+            .child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("session stdin was not piped"))?;
+        let writer = thread::spawn(move || -> Result<ChildStdin> {
+            stdin.write_all(payload.as_bytes())?;
+            Ok(stdin)
+        });
+
+        let mut output = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stdout.read_line(&mut line)? == 0 {
+                break;
+            }
+            if line.trim_end() == marker {
+                break;
+            }
+            output.push_str(&line);
+        }
+
+        self.child.stdin = Some(match writer.join() {
+            Ok(result) => result?,
+            Err(_) => return Err(anyhow!("session stdin writer thread panicked")),
+        });
+
+        Ok(output)
+    }
+
+    /// Close stdin and wait for the interpreter to exit.
+    fn close(mut self) -> Result<()> {
+        self.child.stdin.take();
+        self.child.wait()?;
+        Ok(())
+    }
+}
+
+/// A token unlikely to collide with a block's own output, used to delimit a
+/// session block's output from the next.
+fn random_token() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("{:x}", nanos)
+}
+
+pub struct Executors {
+    langs: HashMap<String, LangExecutor>,
+    sessions: HashMap<String, Session>,
+}
 
 impl Executors {
     pub fn default_executors() -> Self {
-        let mut exs = HashMap::new();
-        exs.insert(
+        let mut langs = HashMap::new();
+        langs.insert(
             "sh".to_owned(),
-            LangExecutor::new("sh".to_owned(), vec!["-c".to_owned()]),
+            LangExecutor::new(
+                "sh".to_owned(),
+                vec!["-c".to_owned()],
+                ExecutionStrategy::Arg,
+                SentinelStyle::Shell,
+                "sh".to_owned(),
+                vec![],
+            ),
         );
-        exs.insert(
+        langs.insert(
             "bash".to_owned(),
-            LangExecutor::new("bash".to_owned(), vec!["-c".to_owned()]),
+            LangExecutor::new(
+                "bash".to_owned(),
+                vec!["-c".to_owned()],
+                ExecutionStrategy::Arg,
+                SentinelStyle::Shell,
+                "bash".to_owned(),
+                vec![],
+            ),
         );
-        exs.insert(
+        langs.insert(
             "shell".to_owned(),
-            LangExecutor::new("sh".to_owned(), vec!["-c".to_owned()]),
+            LangExecutor::new(
+                "sh".to_owned(),
+                vec!["-c".to_owned()],
+                ExecutionStrategy::Arg,
+                SentinelStyle::Shell,
+                "sh".to_owned(),
+                vec![],
+            ),
+        );
+        langs.insert(
+            "python".to_owned(),
+            LangExecutor::new(
+                "python3".to_owned(),
+                vec![],
+                ExecutionStrategy::Stdin,
+                SentinelStyle::Python,
+                "python3".to_owned(),
+                vec!["-i".to_owned(), "-u".to_owned()],
+            ),
+        );
+        langs.insert(
+            "python3".to_owned(),
+            LangExecutor::new(
+                "python3".to_owned(),
+                vec![],
+                ExecutionStrategy::Stdin,
+                SentinelStyle::Python,
+                "python3".to_owned(),
+                vec!["-i".to_owned(), "-u".to_owned()],
+            ),
+        );
+        langs.insert(
+            "ruby".to_owned(),
+            LangExecutor::new(
+                "ruby".to_owned(),
+                vec![],
+                ExecutionStrategy::Stdin,
+                SentinelStyle::Ruby,
+                "ruby".to_owned(),
+                vec!["-e".to_owned(), RUBY_SESSION_DRIVER.to_owned()],
+            ),
+        );
+        langs.insert(
+            "node".to_owned(),
+            LangExecutor::new(
+                "node".to_owned(),
+                vec![],
+                ExecutionStrategy::Stdin,
+                SentinelStyle::Node,
+                "node".to_owned(),
+                vec!["-e".to_owned(), NODE_SESSION_DRIVER.to_owned()],
+            ),
         );
-        Executors(exs)
+        langs.insert(
+            "perl".to_owned(),
+            LangExecutor::new(
+                "perl".to_owned(),
+                vec![],
+                ExecutionStrategy::Stdin,
+                SentinelStyle::Perl,
+                "perl".to_owned(),
+                vec!["-e".to_owned(), PERL_SESSION_DRIVER.to_owned()],
+            ),
+        );
+        Executors {
+            langs,
+            sessions: HashMap::new(),
+        }
     }
 
-    pub fn execute(&self, body: &CodeBlockBody) -> Result<()> {
-        match &body.lang {
-            Some(lang) => {
-                if let Some(ex) = self.0.get(lang) {
-                    ex.execute_body(body)?;
-                }
+    /// Get (spawning lazily if necessary) the session registered under
+    /// `session_name`, using `lang`'s executor to spawn it if it doesn't
+    /// exist yet.
+    fn session(&mut self, lang: &str, session_name: &str) -> Result<&mut Session> {
+        if !self.sessions.contains_key(session_name) {
+            let ex = self
+                .langs
+                .get(lang)
+                .ok_or_else(|| anyhow!("no executor registered for language '{}'", lang))?;
+            self.sessions
+                .insert(session_name.to_owned(), ex.spawn_session()?);
+        }
+        Ok(self.sessions.get_mut(session_name).unwrap())
+    }
+
+    pub fn execute(&mut self, header: &CodeBlockHeader, body: &CodeBlockBody) -> Result<()> {
+        let lang = match &body.lang {
+            Some(lang) => lang.clone(),
+            None => return Ok(()),
+        };
+        if !self.langs.contains_key(&lang) {
+            return Ok(());
+        }
+
+        match &header.session {
+            Some(session_name) => {
+                let output = self.session(&lang, session_name)?.run(&body.code)?;
+                print!("{}", output);
                 Ok(())
             }
-            None => Ok(()),
+            None => self.langs.get(&lang).unwrap().execute_body(body),
         }
     }
+
+    /// Run the body, capturing its output rather than streaming it. Returns
+    /// `None` if there's no registered executor for the body's language.
+    pub fn capture(
+        &mut self,
+        header: &CodeBlockHeader,
+        body: &CodeBlockBody,
+    ) -> Result<Option<Output>> {
+        let lang = match &body.lang {
+            Some(lang) => lang.clone(),
+            None => return Ok(None),
+        };
+        if !self.langs.contains_key(&lang) {
+            return Ok(None);
+        }
+
+        match &header.session {
+            Some(session_name) => {
+                let stdout = self.session(&lang, session_name)?.run(&body.code)?;
+                Ok(Some(Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: stdout.into_bytes(),
+                    stderr: Vec::new(),
+                }))
+            }
+            None => Ok(Some(
+                self.langs.get(&lang).unwrap().execute_body_captured(body)?,
+            )),
+        }
+    }
+
+    /// Close every open session, waiting for each interpreter to exit.
+    pub fn close_sessions(&mut self) -> Result<()> {
+        for (_, session) in self.sessions.drain() {
+            session.close()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markdown::CodeBlockHeader;
+
+    fn session_header(name: &str, session: &str) -> CodeBlockHeader {
+        CodeBlockHeader {
+            name: name.to_owned(),
+            results: false,
+            should_fail: false,
+            session: Some(session.to_owned()),
+            noexec: false,
+            params: Vec::new(),
+        }
+    }
+
+    fn sh_body(code: &str) -> CodeBlockBody {
+        CodeBlockBody {
+            lang: Some("sh".to_owned()),
+            code: code.to_owned(),
+        }
+    }
+
+    #[test]
+    fn session_shares_state_across_blocks() {
+        let mut exs = Executors::default_executors();
+
+        exs.capture(&session_header("one", "shared"), &sh_body("x=5\n"))
+            .unwrap();
+        let second = exs
+            .capture(&session_header("two", "shared"), &sh_body("echo $x\n"))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!("5\n", String::from_utf8_lossy(&second.stdout));
+        exs.close_sessions().unwrap();
+    }
 }
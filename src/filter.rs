@@ -0,0 +1,71 @@
+//! Deciding which code blocks actually run, based on `--only`/`--skip` CLI
+//! tag filters and a block's own `:if` environment guard.
+
+use crate::markdown::CodeBlockHeader;
+
+/// Whether `header` should run given the `--only`/`--skip` tag filters
+/// (matched against `:tags`) and its `:if <VAR>` guard.
+pub fn should_run(header: &CodeBlockHeader, only: Option<&str>, skip: Option<&str>) -> bool {
+    if let Some(var) = header.if_env() {
+        if std::env::var_os(var).is_none() {
+            return false;
+        }
+    }
+
+    let tags = header.tags();
+    if let Some(only) = only {
+        if !tags.contains(&only) {
+            return false;
+        }
+    }
+    if let Some(skip) = skip {
+        if tags.contains(&skip) {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markdown::{Directive, Document};
+
+    fn header_from(content: &str) -> CodeBlockHeader {
+        let mut doc = Document::new(content.as_bytes());
+        match doc.next().expect("expected directive") {
+            Directive::CodeBlock { header, .. } => header,
+        }
+    }
+
+    #[test]
+    fn only_filters_by_tag() {
+        let header = header_from(
+            "<!-- mdbabel :name a :tags slow,integration -->\n```sh\necho hi\n```\n",
+        );
+        assert!(should_run(&header, Some("slow"), None));
+        assert!(!should_run(&header, Some("fast"), None));
+    }
+
+    #[test]
+    fn skip_filters_by_tag() {
+        let header = header_from("<!-- mdbabel :name a :tags slow -->\n```sh\necho hi\n```\n");
+        assert!(!should_run(&header, None, Some("slow")));
+        assert!(should_run(&header, None, Some("fast")));
+    }
+
+    #[test]
+    fn if_env_guards_on_missing_var() {
+        let header = header_from(
+            "<!-- mdbabel :name a :if MDBABEL_TEST_VAR_NOT_SET -->\n```sh\necho hi\n```\n",
+        );
+        assert!(!should_run(&header, None, None));
+    }
+
+    #[test]
+    fn no_filters_runs_by_default() {
+        let header = header_from("<!-- mdbabel :name a -->\n```sh\necho hi\n```\n");
+        assert!(should_run(&header, None, None));
+    }
+}
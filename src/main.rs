@@ -1,31 +1,298 @@
 use clap::{App, Arg};
+use std::collections::HashMap;
 use std::fs::File;
+use std::path::{Path, PathBuf};
 
+mod discover;
 mod executor;
+mod filter;
 mod markdown;
+mod noweb;
+mod results;
+mod tangle;
+mod verify;
+
+/// Tallies accumulated while processing one or more files, printed as a
+/// summary once every discovered file has been handled.
+#[derive(Default)]
+struct RunStats {
+    blocks_executed: usize,
+    failures: usize,
+}
+
+impl RunStats {
+    fn add(&mut self, other: RunStats) {
+        self.blocks_executed += other.blocks_executed;
+        self.failures += other.failures;
+    }
+}
 
 fn main() {
     let matches = App::new("mdbabel")
         .version("0.1.0")
         .about("Execute markdown code blocks")
         .arg(
-            Arg::with_name("INPUT_FILE")
-                .help("Markdown file to read.")
+            Arg::with_name("INPUT")
+                .help(
+                    "Markdown file(s) to read, or directories to search \
+                     recursively for '*.md'/'*.markdown' files.",
+                )
                 .index(1)
+                .multiple(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("results")
+                .long("results")
+                .help(
+                    "Capture output for blocks with a ':results' parameter and \
+                     write it back into the file in place, instead of streaming \
+                     execution.",
+                ),
+        )
+        .arg(Arg::with_name("verify").long("verify").help(
+            "Check blocks with an expected result or ':should-fail' against \
+             their captured output instead of executing normally, exiting \
+             non-zero on any mismatch.",
+        ))
+        .arg(Arg::with_name("tangle").long("tangle").help(
+            "Write blocks with a ':tangle' parameter out to their \
+             destination files instead of executing anything.",
+        ))
+        .arg(
+            Arg::with_name("only")
+                .long("only")
+                .takes_value(true)
+                .value_name("TAG")
+                .help("Only run blocks carrying this tag in ':tags'."),
+        )
+        .arg(
+            Arg::with_name("skip")
+                .long("skip")
+                .takes_value(true)
+                .value_name("TAG")
+                .help("Skip blocks carrying this tag in ':tags'."),
+        )
         .get_matches();
 
-    let file_path = matches.value_of("INPUT_FILE").unwrap();
+    let inputs: Vec<&str> = matches.values_of("INPUT").unwrap().collect();
+    let only = matches.value_of("only");
+    let skip = matches.value_of("skip");
+
+    let files = discover::discover_files(&inputs).unwrap();
+
+    let mut stats = RunStats::default();
+    if matches.is_present("tangle") {
+        // Accumulate every file's destinations before writing anything, so
+        // two files tangling to the same path are concatenated rather than
+        // the last one processed clobbering the rest.
+        let mut destinations: HashMap<PathBuf, String> = HashMap::new();
+        for file in &files {
+            stats.add(run_tangle(file, only, skip, &mut destinations));
+        }
+        tangle::flush(&destinations).unwrap();
+    } else {
+        for file in &files {
+            let file_stats = if matches.is_present("verify") {
+                run_verify(file, only, skip)
+            } else if matches.is_present("results") {
+                run_results(file, only, skip)
+            } else {
+                run_streaming(file, only, skip)
+            };
+            stats.add(file_stats);
+        }
+    }
+
+    if files.len() > 1 {
+        eprintln!(
+            "{} file(s) processed, {} block(s) executed, {} failure(s)",
+            files.len(),
+            stats.blocks_executed,
+            stats.failures,
+        );
+    }
+
+    if stats.failures > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Execute every code block, streaming its output to the inherited stdio.
+fn run_streaming(file_path: &Path, only: Option<&str>, skip: Option<&str>) -> RunStats {
+    let file = File::open(file_path).unwrap();
+    let doc = markdown::Document::new(file);
+
+    // Drain the document up front so noweb references can be resolved across
+    // the whole file before anything runs.
+    let directives: Vec<markdown::Directive> = doc.collect();
+
+    let mut blocks = HashMap::new();
+    for markdown::Directive::CodeBlock { header, body, .. } in &directives {
+        blocks.insert(header.name.clone(), body.clone());
+    }
+
+    let mut stats = RunStats::default();
+    let mut exs = executor::Executors::default_executors();
+    for markdown::Directive::CodeBlock { header, body, .. } in &directives {
+        if header.noexec || !filter::should_run(header, only, skip) {
+            continue;
+        }
+
+        let code = noweb::expand(&header.name, &blocks).unwrap();
+        let expanded_body = markdown::CodeBlockBody {
+            lang: body.lang.clone(),
+            code,
+        };
+        exs.execute(header, &expanded_body).unwrap();
+        stats.blocks_executed += 1;
+    }
+    exs.close_sessions().unwrap();
+    stats
+}
+
+/// Execute blocks carrying a `:results` parameter, capturing their output and
+/// rewriting the file in place with the result.
+fn run_results(file_path: &Path, only: Option<&str>, skip: Option<&str>) -> RunStats {
+    let file = File::open(file_path).unwrap();
+    let doc = markdown::Document::new(file);
+    let mut out_lines = doc.lines().to_vec();
+
+    let directives: Vec<markdown::Directive> = doc.collect();
+
+    let mut blocks = HashMap::new();
+    for markdown::Directive::CodeBlock { header, body, .. } in &directives {
+        blocks.insert(header.name.clone(), body.clone());
+    }
+
+    let mut stats = RunStats::default();
+    let mut exs = executor::Executors::default_executors();
+
+    // Splice from the bottom of the file up so earlier spans stay valid as
+    // later ones are rewritten.
+    for markdown::Directive::CodeBlock {
+        header, body, span, ..
+    } in directives.iter().rev()
+    {
+        if !header.results || header.noexec || !filter::should_run(header, only, skip) {
+            continue;
+        }
+
+        let code = noweb::expand(&header.name, &blocks).unwrap();
+        let expanded_body = markdown::CodeBlockBody {
+            lang: body.lang.clone(),
+            code,
+        };
+
+        if let Some(output) = exs.capture(header, &expanded_body).unwrap() {
+            let block = results::render_results_block(&header.name, &output);
+            results::splice_results(&mut out_lines, span, block);
+            stats.blocks_executed += 1;
+        }
+    }
+    exs.close_sessions().unwrap();
+
+    std::fs::write(file_path, out_lines.concat()).unwrap();
+    stats
+}
+
+/// Check every block with an expected result or ':should-fail' against its
+/// captured output, printing a diff-style summary for any mismatch.
+fn run_verify(file_path: &Path, only: Option<&str>, skip: Option<&str>) -> RunStats {
     let file = File::open(file_path).unwrap();
     let doc = markdown::Document::new(file);
+    let directives: Vec<markdown::Directive> = doc.collect();
+
+    let mut blocks = HashMap::new();
+    for markdown::Directive::CodeBlock { header, body, .. } in &directives {
+        blocks.insert(header.name.clone(), body.clone());
+    }
+
+    let mut stats = RunStats::default();
+    let mut exs = executor::Executors::default_executors();
+    let mut mismatches = Vec::new();
 
-    let exs = executor::Executors::default_executors();
-    for directive in doc {
-        match directive {
-            markdown::Directive::CodeBlock { header: _, body } => {
-                exs.execute(&body).unwrap();
+    for markdown::Directive::CodeBlock {
+        header,
+        body,
+        expected,
+        ..
+    } in &directives
+    {
+        if (expected.is_none() && !header.should_fail)
+            || header.noexec
+            || !filter::should_run(header, only, skip)
+        {
+            continue;
+        }
+
+        let code = noweb::expand(&header.name, &blocks).unwrap();
+        let expanded_body = markdown::CodeBlockBody {
+            lang: body.lang.clone(),
+            code,
+        };
+
+        if let Some(output) = exs.capture(header, &expanded_body).unwrap() {
+            stats.blocks_executed += 1;
+            if let Some(mismatch) = verify::check(
+                &header.name,
+                header.should_fail,
+                expected.as_deref(),
+                &output,
+            ) {
+                mismatches.push(mismatch);
             }
         }
     }
+    exs.close_sessions().unwrap();
+
+    if !mismatches.is_empty() {
+        eprintln!("{}:", file_path.display());
+        for mismatch in &mismatches {
+            eprint!("{}", mismatch);
+        }
+        eprintln!("{} block(s) did not match expectations", mismatches.len());
+    }
+    stats.failures = mismatches.len();
+    stats
+}
+
+/// Collect every block in `file_path` with a ':tangle' parameter into
+/// `destinations`, in document order, instead of executing anything. Shared
+/// across every file being tangled so the caller can flush once all files
+/// have contributed to the same destination.
+fn run_tangle(
+    file_path: &Path,
+    only: Option<&str>,
+    skip: Option<&str>,
+    destinations: &mut HashMap<PathBuf, String>,
+) -> RunStats {
+    let file = File::open(file_path).unwrap();
+    let doc = markdown::Document::new(file);
+    let directives: Vec<markdown::Directive> = doc.collect();
+
+    let mut blocks = HashMap::new();
+    for markdown::Directive::CodeBlock { header, body, .. } in &directives {
+        blocks.insert(header.name.clone(), body.clone());
+    }
+
+    let mut stats = RunStats::default();
+    for markdown::Directive::CodeBlock { header, .. } in &directives {
+        let dest = match header.tangle() {
+            Some(dest) => dest,
+            None => continue,
+        };
+        if !filter::should_run(header, only, skip) {
+            continue;
+        }
+
+        let code = noweb::expand(&header.name, &blocks).unwrap();
+        destinations
+            .entry(PathBuf::from(dest))
+            .or_default()
+            .push_str(&code);
+        stats.blocks_executed += 1;
+    }
+
+    stats
 }
@@ -1,5 +1,5 @@
 use anyhow::{anyhow, Result};
-use std::io::{BufRead, BufReader, Read};
+use std::io::Read;
 use std::iter::Iterator;
 
 /// A comment must start with this prefix in order to be considered a directive
@@ -7,19 +7,61 @@ use std::iter::Iterator;
 const DIRECTIVE_PREFIX: &str = "mdbabel";
 
 const CODEBLOCK_NAME_PARAMETER: &str = ":name";
+const CODEBLOCK_RESULTS_PARAMETER: &str = ":results";
+const CODEBLOCK_SHOULD_FAIL_PARAMETER: &str = ":should-fail";
+const CODEBLOCK_SESSION_PARAMETER: &str = ":session";
+const CODEBLOCK_TAGS_PARAMETER: &str = ":tags";
+const CODEBLOCK_IF_PARAMETER: &str = ":if";
+const CODEBLOCK_TANGLE_PARAMETER: &str = ":tangle";
+const CODEBLOCK_NOEXEC_PARAMETER: &str = ":noexec";
+
+/// Marker comment parameter used to recognize a results block that was
+/// previously written out for a code block, so that reruns replace it
+/// instead of appending another one.
+const RESULTS_FOR_PARAMETER: &str = ":results-for";
+
+/// Marker comment parameter used to recognize the expected-output block
+/// recorded for a code block, checked against in verification mode.
+const EXPECT_FOR_PARAMETER: &str = ":expect-for";
+
+/// A single `:key value` (or bare `:flag`) pair parsed from a header
+/// comment, in the order it appeared. `:flag`-style parameters that take no
+/// value are recorded with a `None` value.
+pub type HeaderParam = (String, Option<String>);
 
 /// Header that can be found in a code block directive.
 #[derive(Debug, PartialEq)]
 pub struct CodeBlockHeader {
     pub name: String,
+    /// Set via `:results output`: the block's captured stdout/stderr is
+    /// written back into the document in place. `:results` only accepts
+    /// `output` — there's no way to capture an evaluated value rather than
+    /// printed output, so no other mode is advertised.
+    pub results: bool,
+    /// Set via `:should-fail`: the block is expected to exit with a non-zero
+    /// status in verification mode.
+    pub should_fail: bool,
+    /// Set via `:session <name>`: the block runs in a long-lived interpreter
+    /// shared with every other block carrying the same session name.
+    pub session: Option<String>,
+    /// Set via `:noexec`: the block is never run on its own, only made
+    /// available for `<<name>>` noweb expansion into other blocks. Used for
+    /// library-style snippets that only make sense spliced into a caller.
+    pub noexec: bool,
+    /// Every `:key value` pair parsed from the header after `:name`, in
+    /// order. Includes the parameters above (other than `:name` itself) as
+    /// well as ones with no dedicated field (e.g.
+    /// `:tags`, `:if`), so new parameters don't need bespoke parsing.
+    pub params: Vec<HeaderParam>,
 }
 
 impl CodeBlockHeader {
     /// Parse a code block header from the contents of a comment string.
     fn from_comment_contents(content: &str) -> Result<Self> {
-        let mut ss = content.split_ascii_whitespace();
+        let tokens: Vec<&str> = content.split_ascii_whitespace().collect();
+        let mut tokens = tokens.into_iter();
 
-        match ss.next() {
+        match tokens.next() {
             Some(s) => {
                 if s != DIRECTIVE_PREFIX {
                     return Err(anyhow!("Header does not contain correct prefix"));
@@ -29,25 +71,139 @@ impl CodeBlockHeader {
         };
 
         // Name should always be the first parameter in the header.
-        let name = match ss.next() {
-            Some(CODEBLOCK_NAME_PARAMETER) => match ss.next() {
+        let name = match tokens.next() {
+            Some(CODEBLOCK_NAME_PARAMETER) => match tokens.next() {
                 Some(s) => s.to_owned(),
                 None => return Err(anyhow!("No value for 'name' parameter")),
             },
             _ => return Err(anyhow!("Header doesn't contain 'name' as first parameter")),
         };
 
-        Ok(CodeBlockHeader { name })
+        let params = parse_params(tokens);
+
+        let results = match param_value(&params, CODEBLOCK_RESULTS_PARAMETER) {
+            Some(Some("output")) => true,
+            Some(Some(other)) => return Err(anyhow!("Unknown 'results' mode '{}'", other)),
+            Some(None) => return Err(anyhow!("No value for 'results' parameter")),
+            None => false,
+        };
+        let should_fail = param_value(&params, CODEBLOCK_SHOULD_FAIL_PARAMETER).is_some();
+        let session = match param_value(&params, CODEBLOCK_SESSION_PARAMETER) {
+            Some(Some(s)) => Some(s.to_owned()),
+            Some(None) => return Err(anyhow!("No value for 'session' parameter")),
+            None => None,
+        };
+        if should_fail && session.is_some() {
+            // A session's real exit status isn't tracked (there's no single
+            // process exit code for one block among many run in a shared
+            // interpreter), so there's nothing honest for ':should-fail' to
+            // check against.
+            return Err(anyhow!(
+                "':should-fail' cannot be combined with ':session'"
+            ));
+        }
+        let noexec = param_value(&params, CODEBLOCK_NOEXEC_PARAMETER).is_some();
+
+        Ok(CodeBlockHeader {
+            name,
+            results,
+            should_fail,
+            session,
+            noexec,
+            params,
+        })
+    }
+
+    /// Tags declared via `:tags a,b,c`, used for `--only`/`--skip` filtering.
+    pub fn tags(&self) -> Vec<&str> {
+        match param_value(&self.params, CODEBLOCK_TAGS_PARAMETER) {
+            Some(Some(tags)) => tags.split(',').collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The environment variable named by `:if <VAR>`, if any: the block
+    /// should be skipped unless this variable is set.
+    pub fn if_env(&self) -> Option<&str> {
+        match param_value(&self.params, CODEBLOCK_IF_PARAMETER) {
+            Some(Some(var)) => Some(var),
+            _ => None,
+        }
+    }
+
+    /// The destination path declared via `:tangle <path>`, if any.
+    pub fn tangle(&self) -> Option<&str> {
+        match param_value(&self.params, CODEBLOCK_TANGLE_PARAMETER) {
+            Some(Some(path)) => Some(path),
+            _ => None,
+        }
     }
 }
 
+/// Parse the remaining header tokens (after `:name <value>`) into an ordered
+/// list of `:key value` pairs. A key is only paired with the following
+/// token as its value if that token isn't itself a key.
+fn parse_params<'a>(tokens: impl Iterator<Item = &'a str>) -> Vec<HeaderParam> {
+    let tokens: Vec<&str> = tokens.collect();
+    let mut params = Vec::new();
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let key = tokens[i];
+        if !key.starts_with(':') {
+            i += 1;
+            continue;
+        }
+
+        match tokens.get(i + 1) {
+            Some(value) if !value.starts_with(':') => {
+                params.push((key.to_owned(), Some((*value).to_owned())));
+                i += 2;
+            }
+            _ => {
+                params.push((key.to_owned(), None));
+                i += 1;
+            }
+        }
+    }
+
+    params
+}
+
+/// Look up `key` in an ordered parameter list, returning `None` if absent,
+/// `Some(None)` if present as a bare flag, or `Some(Some(value))` if present
+/// with a value.
+fn param_value<'a>(params: &'a [HeaderParam], key: &str) -> Option<Option<&'a str>> {
+    params
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_deref())
+}
+
 /// The contents of a code block.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct CodeBlockBody {
     pub lang: Option<String>,
     pub code: String,
 }
 
+/// A half-open range of 0-indexed line numbers within the source document.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LineSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Where a code block (and any results block already following it) sits in
+/// the source document. Used to rewrite the file in place.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CodeBlockSpan {
+    /// Span covering the header comment through the closing code fence.
+    pub block: LineSpan,
+    /// Span of an existing results block immediately following, if any.
+    pub results: Option<LineSpan>,
+}
+
 /// An 'mdbabel' directive parsed from a markdown document.
 #[derive(Debug, PartialEq)]
 pub enum Directive {
@@ -55,95 +211,169 @@ pub enum Directive {
     CodeBlock {
         header: CodeBlockHeader,
         body: CodeBlockBody,
+        span: CodeBlockSpan,
+        /// Expected stdout recorded via an `:expect-for` block, if any.
+        expected: Option<String>,
     },
 }
 
 /// A markdown document to iterate over.
-pub struct Document<R: Read> {
-    reader: BufReader<R>,
-    line_buf: String,
+///
+/// The whole document is read into memory up front (as lines, newlines
+/// included) so that code blocks can be rewritten in place once executed.
+pub struct Document {
+    lines: Vec<String>,
+    pos: usize,
 }
 
-impl<R: Read> Document<R> {
-    pub fn new(reader: R) -> Self {
+impl Document {
+    pub fn new<R: Read>(mut reader: R) -> Self {
+        let mut content = String::new();
+        reader
+            .read_to_string(&mut content)
+            .expect("failed to read document");
         Document {
-            reader: BufReader::new(reader),
-            line_buf: String::new(),
+            lines: split_lines_keep_ends(&content),
+            pos: 0,
+        }
+    }
+
+    /// The raw lines (newlines included) making up the document.
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// If the lines at the current position are a marker block tagged with
+    /// `marker` for `name` (e.g. `<!-- mdbabel :results-for name -->`
+    /// followed by a fenced block), consume them and return their span
+    /// along with the fenced block's content. Otherwise leave the position
+    /// untouched.
+    fn try_consume_marker_block(&mut self, marker: &str, name: &str) -> Option<(LineSpan, String)> {
+        let start = self.pos;
+
+        let content = parse_comment_from_line(self.lines.get(start)?)?;
+        let mut ss = content.split_ascii_whitespace();
+        if ss.next()? != DIRECTIVE_PREFIX {
+            return None;
         }
+        if ss.next()? != marker {
+            return None;
+        }
+        if ss.next()? != name {
+            return None;
+        }
+
+        let mut pos = start + 1;
+        if !is_code_block_del(self.lines.get(pos)?) {
+            return None;
+        }
+        pos += 1;
+
+        let mut body = String::new();
+        loop {
+            let line = self.lines.get(pos)?;
+            pos += 1;
+            if is_code_block_del(line) {
+                break;
+            }
+            body.push_str(line);
+        }
+
+        self.pos = pos;
+        Some((LineSpan { start, end: pos }, body))
     }
 
-    fn read_lines_while<P>(&mut self, predicate: P) -> Result<&str>
-    where
-        P: FnMut(&str) -> bool,
-    {
-        read_lines_while(&mut self.reader, &mut self.line_buf, predicate)
+    /// If the lines at the current position are a results block for `name`,
+    /// consume them and return their span. Otherwise leave the position
+    /// untouched.
+    fn try_consume_results_block(&mut self, name: &str) -> Option<LineSpan> {
+        self.try_consume_marker_block(RESULTS_FOR_PARAMETER, name)
+            .map(|(span, _)| span)
     }
 
-    fn read_next_line(&mut self) -> Result<&str> {
-        self.read_lines_while(|_| false)
+    /// If the lines at the current position are an expected-output block for
+    /// `name`, consume them and return its content. Otherwise leave the
+    /// position untouched.
+    fn try_consume_expect_block(&mut self, name: &str) -> Option<String> {
+        self.try_consume_marker_block(EXPECT_FOR_PARAMETER, name)
+            .map(|(_, body)| body)
     }
 }
 
-impl<R: Read> Iterator for Document<R> {
+impl Iterator for Document {
     type Item = Directive;
 
     fn next(&mut self) -> Option<Self::Item> {
         // Discard all lines that do not have a comment.
-        let discard_pred = |line: &str| parse_comment_from_line(line).is_none();
-        let line = self.read_lines_while(discard_pred).ok()?;
-        let content = parse_comment_from_line(line)?;
+        let (header_start, content) = loop {
+            let line = self.lines.get(self.pos)?;
+            self.pos += 1;
+            if let Some(content) = parse_comment_from_line(line) {
+                break (self.pos - 1, content.to_owned());
+            }
+        };
 
-        let header = match CodeBlockHeader::from_comment_contents(content) {
+        let header = match CodeBlockHeader::from_comment_contents(&content) {
             Ok(header) => header,
             _ => return None,
         };
 
         // Read in starting code block delimeter immediately after the header.
-        let line = self.read_next_line().ok()?;
-        if !is_code_block_del(line) {
+        let delim = self.lines.get(self.pos)?;
+        if !is_code_block_del(delim) {
             return None;
         }
-        let lang = parse_lang_from_code_block_del(line).map(|s| s.to_owned());
+        let lang = parse_lang_from_code_block_del(delim).map(|s| s.to_owned());
+        self.pos += 1;
 
         // Collect all lines inside the code block.
         let mut code = String::new();
-        let code_pred = |line: &str| {
-            let is_code = !is_code_block_del(line);
-            if is_code {
-                code.push_str(line);
+        loop {
+            let line = self.lines.get(self.pos)?;
+            self.pos += 1;
+            if is_code_block_del(line) {
+                break;
             }
-            is_code
+            code.push_str(line);
+        }
+        let block = LineSpan {
+            start: header_start,
+            end: self.pos,
+        };
+
+        let results = self.try_consume_results_block(&header.name);
+        let expected = if results.is_none() {
+            self.try_consume_expect_block(&header.name)
+        } else {
+            None
         };
-        let _ = self.read_lines_while(code_pred).ok()?;
 
         let body = CodeBlockBody { lang, code };
-        Some(Directive::CodeBlock { header, body })
+        let span = CodeBlockSpan { block, results };
+        Some(Directive::CodeBlock {
+            header,
+            body,
+            span,
+            expected,
+        })
     }
 }
 
-/// Read lines from the buffered reader while predicate keeps returning
-/// true. The last read line will be returned.
-fn read_lines_while<'a, R, P>(
-    reader: &mut R,
-    buf: &'a mut String,
-    mut predicate: P,
-) -> Result<&'a str>
-where
-    R: BufRead,
-    P: FnMut(&str) -> bool,
-{
-    buf.truncate(0);
-    let mut n = reader.read_line(buf)?;
-    let mut line = &buf[0..n];
-    while predicate(line) {
-        buf.truncate(0);
-        n = reader.read_line(buf)?;
-        if n == 0 {
-            return Err(anyhow!("End of file"));
+/// Split `content` into its lines, keeping the trailing newline of each line
+/// that has one.
+fn split_lines_keep_ends(content: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (idx, ch) in content.char_indices() {
+        if ch == '\n' {
+            lines.push(content[start..=idx].to_owned());
+            start = idx + 1;
         }
-        line = &buf[0..n];
     }
-    Ok(&buf[0..n])
+    if start < content.len() {
+        lines.push(content[start..].to_owned());
+    }
+    lines
 }
 
 /// If a line contains a comment, return the substring containing just the
@@ -241,15 +471,198 @@ mod tests {
         let expected = Directive::CodeBlock {
             header: CodeBlockHeader {
                 name: "test-block".to_owned(),
+                results: false,
+                should_fail: false,
+                session: None,
+                noexec: false,
+                params: Vec::new(),
             },
             body: CodeBlockBody {
                 lang: Some("sh".to_owned()),
                 code: "echo 'hello world'\n".to_owned(),
             },
+            span: CodeBlockSpan {
+                block: LineSpan { start: 4, end: 8 },
+                results: None,
+            },
+            expected: None,
         };
         assert_eq!(expected, directive);
 
         let next = doc.next();
         assert_eq!(None, next);
     }
+
+    #[test]
+    fn read_document_with_results_parameter() {
+        let content = "\
+            <!-- mdbabel :name test-block :results output -->\n\
+            ```sh\n\
+            echo 'hello world'\n\
+            ```\n";
+
+        let mut doc = Document::new(content.as_bytes());
+        let directive = doc.next().expect("expected code block directive");
+        match directive {
+            Directive::CodeBlock { header, .. } => {
+                assert_eq!("test-block", header.name);
+                assert!(header.results);
+            }
+        }
+    }
+
+    #[test]
+    fn results_value_mode_is_rejected() {
+        let err = CodeBlockHeader::from_comment_contents("mdbabel :name test-block :results value")
+            .unwrap_err();
+        assert!(err.to_string().contains("Unknown 'results' mode"));
+    }
+
+    #[test]
+    fn should_fail_with_session_is_rejected() {
+        let err = CodeBlockHeader::from_comment_contents(
+            "mdbabel :name test-block :session repl :should-fail",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("should-fail"));
+    }
+
+    #[test]
+    fn read_document_recognizes_existing_results_block() {
+        let content = "\
+            <!-- mdbabel :name test-block :results output -->\n\
+            ```sh\n\
+            echo 'hello world'\n\
+            ```\n\
+            <!-- mdbabel :results-for test-block -->\n\
+            ```text\n\
+            hello world\n\
+            ```\n\
+            \n\
+            More text.\n";
+
+        let mut doc = Document::new(content.as_bytes());
+        let directive = doc.next().expect("expected code block directive");
+        match directive {
+            Directive::CodeBlock { span, .. } => {
+                assert_eq!(LineSpan { start: 0, end: 4 }, span.block);
+                assert_eq!(Some(LineSpan { start: 4, end: 8 }), span.results);
+            }
+        }
+
+        assert_eq!(None, doc.next());
+    }
+
+    #[test]
+    fn read_document_with_should_fail_parameter() {
+        let content = "\
+            <!-- mdbabel :name test-block :should-fail -->\n\
+            ```sh\n\
+            exit 1\n\
+            ```\n";
+
+        let mut doc = Document::new(content.as_bytes());
+        let directive = doc.next().expect("expected code block directive");
+        match directive {
+            Directive::CodeBlock { header, .. } => {
+                assert_eq!("test-block", header.name);
+                assert!(header.should_fail);
+            }
+        }
+    }
+
+    #[test]
+    fn read_document_with_session_parameter() {
+        let content = "\
+            <!-- mdbabel :name test-block :session repl -->\n\
+            ```sh\n\
+            echo 'hello world'\n\
+            ```\n";
+
+        let mut doc = Document::new(content.as_bytes());
+        let directive = doc.next().expect("expected code block directive");
+        match directive {
+            Directive::CodeBlock { header, .. } => {
+                assert_eq!(Some("repl".to_owned()), header.session);
+            }
+        }
+    }
+
+    #[test]
+    fn read_document_with_tags_and_if_parameters() {
+        let content = "\
+            <!-- mdbabel :name test-block :tags slow,integration :if CI -->\n\
+            ```sh\n\
+            echo 'hello world'\n\
+            ```\n";
+
+        let mut doc = Document::new(content.as_bytes());
+        let directive = doc.next().expect("expected code block directive");
+        match directive {
+            Directive::CodeBlock { header, .. } => {
+                assert_eq!(vec!["slow", "integration"], header.tags());
+                assert_eq!(Some("CI"), header.if_env());
+            }
+        }
+    }
+
+    #[test]
+    fn read_document_with_noexec_parameter() {
+        let content = "\
+            <!-- mdbabel :name helper :noexec -->\n\
+            ```sh\n\
+            echo 'hello world'\n\
+            ```\n";
+
+        let mut doc = Document::new(content.as_bytes());
+        let directive = doc.next().expect("expected code block directive");
+        match directive {
+            Directive::CodeBlock { header, .. } => {
+                assert!(header.noexec);
+            }
+        }
+    }
+
+    #[test]
+    fn read_document_with_tangle_parameter() {
+        let content = "\
+            <!-- mdbabel :name test-block :tangle src/lib.rs -->\n\
+            ```rust\n\
+            fn main() {}\n\
+            ```\n";
+
+        let mut doc = Document::new(content.as_bytes());
+        let directive = doc.next().expect("expected code block directive");
+        match directive {
+            Directive::CodeBlock { header, .. } => {
+                assert_eq!(Some("src/lib.rs"), header.tangle());
+            }
+        }
+    }
+
+    #[test]
+    fn read_document_recognizes_expect_block() {
+        let content = "\
+            <!-- mdbabel :name test-block -->\n\
+            ```sh\n\
+            echo 'hello world'\n\
+            ```\n\
+            <!-- mdbabel :expect-for test-block -->\n\
+            ```text\n\
+            hello world\n\
+            ```\n";
+
+        let mut doc = Document::new(content.as_bytes());
+        let directive = doc.next().expect("expected code block directive");
+        match directive {
+            Directive::CodeBlock {
+                span, expected, ..
+            } => {
+                assert_eq!(None, span.results);
+                assert_eq!(Some("hello world\n".to_owned()), expected);
+            }
+        }
+
+        assert_eq!(None, doc.next());
+    }
 }
@@ -0,0 +1,171 @@
+//! Org-babel/noweb-style expansion of `<<name>>` references between code
+//! blocks.
+
+use crate::markdown::CodeBlockBody;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+const REFERENCE_PREFIX: &str = "<<";
+const REFERENCE_SUFFIX: &str = ">>";
+
+/// Expand the noweb references in the named block, textually substituting in
+/// the (recursively expanded) code of any block it references.
+///
+/// Reference cycles are detected and reported as an error naming the cycle.
+pub fn expand(name: &str, blocks: &HashMap<String, CodeBlockBody>) -> Result<String> {
+    expand_code(name, blocks, &mut Vec::new())
+}
+
+fn expand_code(name: &str, blocks: &HashMap<String, CodeBlockBody>, visited: &mut Vec<String>) -> Result<String> {
+    if visited.iter().any(|v| v == name) {
+        let mut path = visited.clone();
+        path.push(name.to_owned());
+        return Err(anyhow!("noweb reference cycle detected: {}", path.join(" -> ")));
+    }
+
+    let body = blocks
+        .get(name)
+        .ok_or_else(|| anyhow!("no code block named '{}'", name))?;
+
+    visited.push(name.to_owned());
+    let mut out = String::new();
+    for line in body.code.lines() {
+        out.push_str(&expand_line(line, blocks, visited)?);
+        out.push('\n');
+    }
+    visited.pop();
+
+    Ok(out)
+}
+
+/// Expand all `<<name>>` references found in a single line, applying the
+/// whitespace preceding each reference as an indentation prefix to every
+/// inserted line.
+fn expand_line(line: &str, blocks: &HashMap<String, CodeBlockBody>, visited: &mut Vec<String>) -> Result<String> {
+    let refs = references_in_line(line);
+    if refs.is_empty() {
+        return Ok(line.to_owned());
+    }
+
+    let mut out = String::new();
+    let mut last_end = 0;
+    for (start, end, name) in refs {
+        let prefix = &line[last_end..start];
+        let indent = if prefix.chars().all(char::is_whitespace) {
+            prefix
+        } else {
+            ""
+        };
+        out.push_str(prefix);
+
+        let expanded = expand_code(name, blocks, visited)?;
+        let mut lines = expanded.lines();
+        if let Some(first) = lines.next() {
+            out.push_str(first);
+        }
+        for inner in lines {
+            out.push('\n');
+            out.push_str(indent);
+            out.push_str(inner);
+        }
+
+        last_end = end;
+    }
+    out.push_str(&line[last_end..]);
+
+    Ok(out)
+}
+
+/// Find all `<<name>>` references in a line, returning the byte span of each
+/// reference (including the delimeters) along with the referenced name.
+fn references_in_line(line: &str) -> Vec<(usize, usize, &str)> {
+    let mut refs = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = line[offset..].find(REFERENCE_PREFIX) {
+        let name_start = offset + start + REFERENCE_PREFIX.len();
+        match line[name_start..].find(REFERENCE_SUFFIX) {
+            Some(end) => {
+                let name_end = name_start + end;
+                refs.push((offset + start, name_end + REFERENCE_SUFFIX.len(), &line[name_start..name_end]));
+                offset = name_end + REFERENCE_SUFFIX.len();
+            }
+            None => break,
+        }
+    }
+
+    refs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(code: &str) -> CodeBlockBody {
+        CodeBlockBody {
+            lang: Some("sh".to_owned()),
+            code: code.to_owned(),
+        }
+    }
+
+    #[test]
+    fn expand_no_references() {
+        let mut blocks = HashMap::new();
+        blocks.insert("a".to_owned(), block("echo hello\n"));
+
+        let expanded = expand("a", &blocks).unwrap();
+        assert_eq!("echo hello\n", expanded);
+    }
+
+    #[test]
+    fn expand_own_line_reference_preserves_indentation() {
+        let mut blocks = HashMap::new();
+        blocks.insert("helper".to_owned(), block("echo one\necho two\n"));
+        blocks.insert("main".to_owned(), block("if true; then\n    <<helper>>\nfi\n"));
+
+        let expanded = expand("main", &blocks).unwrap();
+        assert_eq!(
+            "if true; then\n    echo one\n    echo two\nfi\n",
+            expanded
+        );
+    }
+
+    #[test]
+    fn expand_inline_reference() {
+        let mut blocks = HashMap::new();
+        blocks.insert("name".to_owned(), block("world"));
+        blocks.insert("main".to_owned(), block("echo hello <<name>>\n"));
+
+        let expanded = expand("main", &blocks).unwrap();
+        assert_eq!("echo hello world\n", expanded);
+    }
+
+    #[test]
+    fn expand_recursive_reference() {
+        let mut blocks = HashMap::new();
+        blocks.insert("a".to_owned(), block("echo a\n"));
+        blocks.insert("b".to_owned(), block("<<a>>\necho b\n"));
+        blocks.insert("c".to_owned(), block("<<b>>\necho c\n"));
+
+        let expanded = expand("c", &blocks).unwrap();
+        assert_eq!("echo a\necho b\necho c\n", expanded);
+    }
+
+    #[test]
+    fn expand_detects_cycle() {
+        let mut blocks = HashMap::new();
+        blocks.insert("a".to_owned(), block("<<b>>\n"));
+        blocks.insert("b".to_owned(), block("<<a>>\n"));
+
+        let err = expand("a", &blocks).unwrap_err();
+        assert!(err.to_string().contains("cycle"));
+    }
+
+    #[test]
+    fn expand_missing_reference_errors() {
+        let mut blocks = HashMap::new();
+        blocks.insert("a".to_owned(), block("<<missing>>\n"));
+
+        assert!(expand("a", &blocks).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+//! Rendering and splicing of `:results` output back into a markdown document.
+
+use crate::markdown::{CodeBlockSpan, LineSpan};
+use std::process::Output;
+
+/// Language tag used for the fenced block a captured result is written into.
+const RESULTS_BLOCK_LANG: &str = "text";
+
+/// Render a captured process `Output` as the lines (newlines included) of a
+/// results block, tagged with the `:results-for` marker comment so it's
+/// recognized and replaced on subsequent runs.
+pub fn render_results_block(name: &str, output: &Output) -> Vec<String> {
+    let mut content = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.stderr.is_empty() {
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&String::from_utf8_lossy(&output.stderr));
+    }
+
+    let mut lines = vec![format!("<!-- mdbabel :results-for {} -->\n", name)];
+    lines.push(format!("```{}\n", RESULTS_BLOCK_LANG));
+    for line in content.lines() {
+        lines.push(format!("{}\n", line));
+    }
+    lines.push("```\n".to_owned());
+    lines
+}
+
+/// Splice a rendered results block into `lines`, replacing the code block's
+/// existing results block if it has one, or inserting a new one immediately
+/// after the code block otherwise.
+pub fn splice_results(lines: &mut Vec<String>, span: &CodeBlockSpan, block: Vec<String>) {
+    match span.results {
+        Some(LineSpan { start, end }) => {
+            lines.splice(start..end, block);
+        }
+        None => {
+            lines.splice(span.block.end..span.block.end, block);
+        }
+    }
+}
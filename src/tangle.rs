@@ -0,0 +1,21 @@
+//! Tangle mode: write named blocks' (noweb-expanded) code out to source
+//! files named by their `:tangle` destination, instead of executing them.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Write each destination's accumulated code to disk, creating parent
+/// directories as needed.
+pub fn flush(destinations: &HashMap<PathBuf, String>) -> Result<()> {
+    for (path, content) in destinations {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        fs::write(path, content)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! Verification mode: run code blocks and check their captured output
+//! against recorded expectations, so the tool can assert a markdown file's
+//! examples still behave as documented (e.g. in CI against a README).
+
+use std::fmt;
+use std::process::Output;
+
+/// A block whose captured result didn't match what was expected of it.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub name: String,
+    expected_stdout: Option<String>,
+    actual_stdout: String,
+    should_fail: bool,
+    succeeded: bool,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "block '{}' did not match expectations:", self.name)?;
+        if self.should_fail == self.succeeded {
+            writeln!(
+                f,
+                "  expected exit: {}",
+                if self.should_fail { "failure" } else { "success" }
+            )?;
+            writeln!(
+                f,
+                "  actual exit:   {}",
+                if self.succeeded { "success" } else { "failure" }
+            )?;
+        }
+        if let Some(expected) = &self.expected_stdout {
+            if expected.trim() != self.actual_stdout.trim() {
+                writeln!(f, "  expected output:\n{}", indent(expected.trim()))?;
+                writeln!(f, "  actual output:\n{}", indent(self.actual_stdout.trim()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn indent(s: &str) -> String {
+    s.lines()
+        .map(|line| format!("    {}\n", line))
+        .collect::<String>()
+}
+
+/// Compare a block's captured output against its recorded expectations,
+/// returning a `Mismatch` describing what's wrong if anything doesn't match.
+pub fn check(
+    name: &str,
+    should_fail: bool,
+    expected_stdout: Option<&str>,
+    output: &Output,
+) -> Option<Mismatch> {
+    let actual_stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let succeeded = output.status.success();
+
+    let status_mismatch = should_fail == succeeded;
+    let stdout_mismatch = expected_stdout
+        .map(|expected| expected.trim() != actual_stdout.trim())
+        .unwrap_or(false);
+
+    if !status_mismatch && !stdout_mismatch {
+        return None;
+    }
+
+    Some(Mismatch {
+        name: name.to_owned(),
+        expected_stdout: expected_stdout.map(|s| s.to_owned()),
+        actual_stdout,
+        should_fail,
+        succeeded,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    fn output(stdout: &str, code: i32) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn check_matching_output_has_no_mismatch() {
+        let out = output("hello\n", 0);
+        assert!(check("block", false, Some("hello"), &out).is_none());
+    }
+
+    #[test]
+    fn check_mismatched_output_is_reported() {
+        let out = output("goodbye\n", 0);
+        let mismatch = check("block", false, Some("hello"), &out).unwrap();
+        assert_eq!("block", mismatch.name);
+    }
+
+    #[test]
+    fn check_should_fail_mismatch() {
+        let out = output("", 0);
+        assert!(check("block", true, None, &out).is_some());
+    }
+}